@@ -1,14 +1,16 @@
 use axum::{extract::{State, Query}, http::StatusCode, response::IntoResponse, routing::get, Router};
-use axum::http::{Request, HeaderMap, HeaderValue};
+use axum::http::{Request, HeaderMap, HeaderName, HeaderValue};
 use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation, TokenData};
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet, KeyAlgorithm, PublicKeyUse};
 use serde::Deserialize;
 use std::sync::Arc;
 use std::collections::{HashMap, HashSet};
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock, Mutex};
 use reqwest::Client;
 use std::env;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{info, debug, warn, error};
+use futures::future::join_all;
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
 #[derive(Debug, Deserialize)]
@@ -17,7 +19,12 @@ struct Claims {
     iss: String,
     #[serde(deserialize_with = "deserialize_audience")]
     aud: String,
-    email: Option<String>
+    email: Option<String>,
+    sub: Option<String>,
+    // Catches custom claims (e.g. Cloudflare Access `identity`/group claims) so
+    // they can be forwarded upstream without a hardcoded field per provider.
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
 
 // Custom deserializer to handle audience as either string or array
@@ -60,45 +67,219 @@ where
     deserializer.deserialize_any(AudienceVisitor)
 }
 
-#[derive(Debug, Deserialize)]
-struct Jwk {
-    kid: String,
-    n: String,
-    e: String,
-    kty: String,
-    alg: String,
-    #[serde(rename = "use")]
-    use_: String,
-}
-
-#[derive(Debug, Deserialize)]
-struct Jwks {
-    keys: Vec<Jwk>,
+// A single Cloudflare Access team this validator accepts tokens from.
+#[derive(Debug, Clone)]
+struct Team {
+    name: String,
+    issuer: String,
+    jwks_url: String,
 }
 
 #[derive(Debug, Clone)]
 struct AppConfig {
-    cf_issuer: String,
-    cf_jwks_url: String,
+    teams: Vec<Team>,
+    default_refresh_secs: u64,
+    min_refresh_secs: u64,
+    max_refresh_secs: u64,
+    retry_backoff_secs: u64,
+    miss_refresh_cooldown_secs: u64,
+    // Ordered (claim name, header name) pairs copied from validated claims onto
+    // the 204 response so nginx's auth_request_set can re-inject them upstream.
+    forwarded_claims: Vec<(String, String)>,
+    // Authorization policy applied after signature validation succeeds. Empty
+    // (the default) means "any authenticated identity is authorized".
+    default_policy: AuthzPolicy,
 }
 
 impl AppConfig {
     fn from_env() -> Result<Self, String> {
-        let team_name = env::var("CF_TEAM_NAME")
-            .map_err(|_| "CF_TEAM_NAME environment variable is required".to_string())?;
-        
-        let cf_issuer = format!("https://{}.cloudflareaccess.com", team_name);
-        let cf_jwks_url = format!("https://{}.cloudflareaccess.com/cdn-cgi/access/certs", team_name);
-        
+        let team_names = env::var("CF_TEAM_NAMES")
+            .map_err(|_| "CF_TEAM_NAMES environment variable is required".to_string())?;
+
+        let teams: Vec<Team> = team_names
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| Team {
+                name: name.to_string(),
+                issuer: format!("https://{}.cloudflareaccess.com", name),
+                jwks_url: format!("https://{}.cloudflareaccess.com/cdn-cgi/access/certs", name),
+            })
+            .collect();
+
+        if teams.is_empty() {
+            return Err("CF_TEAM_NAMES must contain at least one team name".to_string());
+        }
+
+        let default_refresh_secs = env_u64("CF_JWKS_DEFAULT_REFRESH_SECS", 12 * 60 * 60);
+        let min_refresh_secs = env_u64("CF_JWKS_MIN_REFRESH_SECS", 60);
+        let max_refresh_secs = env_u64("CF_JWKS_MAX_REFRESH_SECS", 24 * 60 * 60);
+        let retry_backoff_secs = env_u64("CF_JWKS_RETRY_BACKOFF_SECS", 30);
+        let miss_refresh_cooldown_secs = env_u64("CF_JWKS_MISS_REFRESH_COOLDOWN_SECS", 5);
+        let forwarded_claims = parse_claim_mappings(
+            "CF_FORWARD_CLAIMS",
+            "email:X-Auth-Email,sub:X-Auth-Sub,iss:X-Auth-Issuer",
+        );
+        let default_policy = AuthzPolicy {
+            allowed_emails: parse_policy_set("AUTH_ALLOWED_EMAILS"),
+            allowed_domains: parse_policy_set("AUTH_ALLOWED_DOMAINS"),
+            allowed_groups: parse_policy_set("AUTH_ALLOWED_GROUPS"),
+        };
+
         Ok(AppConfig {
-            cf_issuer,
-            cf_jwks_url,
+            teams,
+            default_refresh_secs,
+            min_refresh_secs,
+            max_refresh_secs,
+            retry_backoff_secs,
+            miss_refresh_cooldown_secs,
+            forwarded_claims,
+            default_policy,
         })
     }
 }
 
+// Read a u64 from the environment, falling back to `default` when unset or unparsable.
+fn env_u64(key: &str, default: u64) -> u64 {
+    env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+// Parse a "claim:Header-Name,claim:Header-Name" env value into ordered pairs.
+fn parse_claim_mappings(env_var: &str, default: &str) -> Vec<(String, String)> {
+    env::var(env_var)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .filter_map(|pair| {
+            let (claim, header) = pair.trim().split_once(':')?;
+            let (claim, header) = (claim.trim(), header.trim());
+            if claim.is_empty() || header.is_empty() {
+                None
+            } else {
+                Some((claim.to_string(), header.to_string()))
+            }
+        })
+        .collect()
+}
+
+// Authorization policy: who is allowed through once a token's signature has
+// already been verified. An empty policy authorizes any authenticated identity.
+#[derive(Debug, Clone, Default)]
+struct AuthzPolicy {
+    allowed_emails: HashSet<String>,
+    allowed_domains: HashSet<String>,
+    allowed_groups: HashSet<String>,
+}
+
+impl AuthzPolicy {
+    fn is_empty(&self) -> bool {
+        self.allowed_emails.is_empty() && self.allowed_domains.is_empty() && self.allowed_groups.is_empty()
+    }
+
+    fn allows(&self, email: Option<&str>, groups: &[String]) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        if let Some(email) = email {
+            if self.allowed_emails.contains(email) {
+                return true;
+            }
+            if let Some(domain) = email.split('@').nth(1) {
+                if self.allowed_domains.contains(domain) {
+                    return true;
+                }
+            }
+        }
+
+        groups.iter().any(|group| self.allowed_groups.contains(group))
+    }
+}
+
+// Parse a comma-separated env var into a set, ignoring blank entries.
+fn parse_policy_set(env_var: &str) -> HashSet<String> {
+    parse_comma_set(&env::var(env_var).unwrap_or_default())
+}
+
+fn parse_comma_set(value: &str) -> HashSet<String> {
+    value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+// Resolve the policy for a specific `aud`: each of AUTH_ALLOWED_EMAILS/DOMAINS/GROUPS
+// can be overridden per audience via a `<VAR>__<AUD_KEY>` env var, falling back to
+// the global policy for any field that has no such override.
+fn policy_for_audience(config: &AppConfig, aud: &str) -> AuthzPolicy {
+    let suffix = env_key_for_aud(aud);
+    debug!("Per-audience policy override suffix for '{}' is '{}' (e.g. AUTH_ALLOWED_EMAILS__{})", aud, suffix, suffix);
+    AuthzPolicy {
+        allowed_emails: env_policy_override("AUTH_ALLOWED_EMAILS", &suffix)
+            .unwrap_or_else(|| config.default_policy.allowed_emails.clone()),
+        allowed_domains: env_policy_override("AUTH_ALLOWED_DOMAINS", &suffix)
+            .unwrap_or_else(|| config.default_policy.allowed_domains.clone()),
+        allowed_groups: env_policy_override("AUTH_ALLOWED_GROUPS", &suffix)
+            .unwrap_or_else(|| config.default_policy.allowed_groups.clone()),
+    }
+}
+
+fn env_policy_override(base_var: &str, aud_suffix: &str) -> Option<HashSet<String>> {
+    env::var(format!("{}__{}", base_var, aud_suffix)).ok().map(|v| parse_comma_set(&v))
+}
+
+// Derive the env var suffix for an audience. Folding punctuation to `_` would let
+// two distinct URL-shaped audiences (e.g. differing only in scheme or a trailing
+// slash) collapse onto the same override var, so hash the whole value instead.
+// Uses FNV-1a rather than std's DefaultHasher: DefaultHasher's algorithm is
+// explicitly documented as unspecified and may change across std/toolchain
+// versions, which would silently stop every per-audience override from matching
+// after an upgrade. FNV-1a is a fixed, publicly specified algorithm an operator
+// can reproduce outside this binary to compute the right env var name (the
+// suffix is also logged at debug level in `policy_for_audience` for discovery).
+fn env_key_for_aud(aud: &str) -> String {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in aud.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    format!("{:016X}", hash)
+}
+
+// Parse the `max-age=<seconds>` directive out of a Cache-Control header value.
+fn parse_max_age(cache_control: &str) -> Option<u64> {
+    cache_control
+        .split(',')
+        .map(|directive| directive.trim())
+        .find_map(|directive| directive.strip_prefix("max-age="))
+        .and_then(|secs| secs.trim().parse().ok())
+}
+
+// Last-known JWKS refresh cadence for one team, surfaced by the manual refresh endpoint.
+struct RefreshInfo {
+    max_age: Option<u64>,
+    next_refresh_secs: u64,
+}
+
 struct AppState {
-    jwks: RwLock<HashMap<String, DecodingKey>>,
+    // issuer -> (kid -> (key, algorithm)); each team's keys live under its own issuer
+    // so a slow/failing team can't wipe out another team's still-valid cache.
+    jwks: RwLock<HashMap<String, HashMap<String, (DecodingKey, Algorithm)>>>,
+    // issuer -> refresh cadence
+    refresh_info: RwLock<HashMap<String, RefreshInfo>>,
+    // Single-flight coordination for on-miss refreshes: only one concurrent
+    // cache-miss task actually fetches. Followers wait on `refresh_generation`,
+    // a counter the leader bumps on completion; unlike `Notify::notify_waiters`,
+    // a `watch` receiver that subscribes before the bump can never miss it, since
+    // `wait_for` compares against the channel's current value rather than relying
+    // on the receiver having already registered as a waiter.
+    refreshing: Mutex<bool>,
+    refresh_generation: watch::Sender<u64>,
+    last_miss_refresh: Mutex<Option<Instant>>,
     client: Client,
     config: Arc<AppConfig>,
 }
@@ -115,74 +296,262 @@ impl AppState {
             .build()
             .expect("Failed to create HTTP client");
 
+        let (refresh_generation, _) = watch::channel(0u64);
+
+        // Seed every team with the default cadence up front, so each configured
+        // team has an entry before its first successful fetch; a team whose startup
+        // fetch fails is then moved onto the retry backoff by `apply_retry_cadence`.
+        let refresh_info = config
+            .teams
+            .iter()
+            .map(|team| (team.issuer.clone(), RefreshInfo { max_age: None, next_refresh_secs: config.default_refresh_secs }))
+            .collect();
+
         Self {
             jwks: RwLock::new(HashMap::new()),
+            refresh_info: RwLock::new(refresh_info),
+            refreshing: Mutex::new(false),
+            refresh_generation,
+            last_miss_refresh: Mutex::new(None),
             client,
             config,
         }
     }
 
-    // Fetch and cache all JWKS keys
-    async fn fetch_and_cache_keys(&self) -> Result<(), ()> {
-        info!("Fetching JWKS keys from: {}", self.config.cf_jwks_url);
-        
-        let keys = fetch_jwks(&self.client, &self.config.cf_jwks_url).await?;
-        info!("Successfully fetched {} keys from JWKS", keys.len());
-        
-        let mut jwks = self.jwks.write().await;
-        jwks.clear(); // Clear existing cache
-        
+    // Reset `next_refresh_secs` to the retry backoff for exactly the teams that
+    // failed a fetch, leaving any team that succeeded in the same round alone.
+    async fn apply_retry_cadence(&self, failed_issuers: &[&str]) {
+        let mut refresh_info = self.refresh_info.write().await;
+        for issuer in failed_issuers {
+            refresh_info
+                .entry((*issuer).to_string())
+                .or_insert(RefreshInfo { max_age: None, next_refresh_secs: self.config.retry_backoff_secs })
+                .next_refresh_secs = self.config.retry_backoff_secs;
+        }
+    }
+
+    // Fetch and cache the JWKS for a single team, without disturbing any other
+    // team's cache.
+    async fn fetch_and_cache_keys_for_team(&self, team: &Team) -> Result<(), ()> {
+        info!("Fetching JWKS keys for team '{}' from: {}", team.name, team.jwks_url);
+
+        let (jwk_set, max_age) = fetch_jwks(&self.client, &team.jwks_url).await?;
+        info!("Successfully fetched {} keys for team '{}'", jwk_set.keys.len(), team.name);
+
+        let next_refresh_secs = max_age
+            .unwrap_or(self.config.default_refresh_secs)
+            .clamp(self.config.min_refresh_secs, self.config.max_refresh_secs);
+        {
+            let mut refresh_info = self.refresh_info.write().await;
+            refresh_info.insert(team.issuer.clone(), RefreshInfo { max_age, next_refresh_secs });
+        }
+
+        let mut team_keys = HashMap::new();
         let mut successful_keys = 0;
-        for jwk in &keys {
-            // Validate JWK properties before processing
-            if jwk.kty != "RSA" {
-                warn!("Skipping non-RSA key: {} (type: {})", jwk.kid, jwk.kty);
+        for jwk in &jwk_set.keys {
+            let Some(kid) = &jwk.common.key_id else {
+                warn!("Skipping JWK without a 'kid' for team '{}'", team.name);
                 continue;
+            };
+
+            if let Some(use_) = &jwk.common.public_key_use {
+                if *use_ != PublicKeyUse::Signature {
+                    warn!("Skipping non-signature key: {}", kid);
+                    continue;
+                }
             }
-            
-            if jwk.alg != "RS256" {
-                warn!("Skipping non-RS256 key: {} (algorithm: {})", jwk.kid, jwk.alg);
-                continue;
+
+            let built = match &jwk.algorithm {
+                AlgorithmParameters::RSA(rsa) => {
+                    let alg = jwk.common.key_algorithm.and_then(key_algorithm_to_algorithm).unwrap_or(Algorithm::RS256);
+                    DecodingKey::from_rsa_components(&rsa.n, &rsa.e).map(|key| (key, alg))
+                }
+                AlgorithmParameters::EllipticCurve(ec) => {
+                    let alg = match &ec.curve {
+                        EllipticCurve::P256 => Algorithm::ES256,
+                        EllipticCurve::P384 => Algorithm::ES384,
+                        other => {
+                            warn!("Skipping EC key '{}' with unsupported curve {:?}", kid, other);
+                            continue;
+                        }
+                    };
+                    DecodingKey::from_ec_components(&ec.x, &ec.y).map(|key| (key, alg))
+                }
+                AlgorithmParameters::OctetKeyPair(okp) => {
+                    if okp.curve != EllipticCurve::Ed25519 {
+                        warn!("Skipping OKP key '{}' with unsupported curve {:?}", kid, okp.curve);
+                        continue;
+                    }
+                    DecodingKey::from_ed_components(&okp.x).map(|key| (key, Algorithm::EdDSA))
+                }
+                AlgorithmParameters::OctetKey(_) => {
+                    warn!("Skipping symmetric (oct) key '{}': not supported for JWT verification here", kid);
+                    continue;
+                }
+            };
+
+            match built {
+                Ok((decoding_key, alg)) => {
+                    debug!("Cached key: {} ({:?}) for team '{}'", kid, alg, team.name);
+                    team_keys.insert(kid.clone(), (decoding_key, alg));
+                    successful_keys += 1;
+                }
+                Err(e) => warn!("Failed to build decoding key for '{}': {:?}", kid, e),
             }
-            
-            if jwk.use_ != "sig" {
-                warn!("Skipping non-signature key: {} (use: {})", jwk.kid, jwk.use_);
-                continue;
+        }
+
+        info!("Successfully cached {}/{} JWKS keys for team '{}'", successful_keys, jwk_set.keys.len(), team.name);
+        self.jwks.write().await.insert(team.issuer.clone(), team_keys);
+        Ok(())
+    }
+
+    // Refresh every configured team's JWKS concurrently, returning each team's
+    // own result so callers that care about per-team cadence (like the periodic
+    // refresh task) don't have to treat one team's failure as every team's.
+    async fn fetch_and_cache_keys_per_team(&self) -> Vec<(String, Result<(), ()>)> {
+        join_all(self.config.teams.iter().map(|team| async move {
+            (team.issuer.clone(), self.fetch_and_cache_keys_for_team(team).await)
+        }))
+        .await
+    }
+
+    // Refresh every configured team's JWKS concurrently. A single team's failure
+    // doesn't prevent the others from refreshing, but is still reported as an
+    // overall error (logged on the cache-miss path, a 500 from `/refresh-keys`).
+    async fn fetch_and_cache_keys(&self) -> Result<(), ()> {
+        let results = self.fetch_and_cache_keys_per_team().await;
+
+        if results.iter().all(|(_, r)| r.is_ok()) {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    // Resolve a cache-miss `kid` without letting a burst of misses stampede the
+    // JWKS endpoint: only the first task to observe the miss actually fetches,
+    // concurrent tasks wait on `refresh_generation` and then re-read the cache.
+    async fn refresh_on_cache_miss(&self, kid: &str) -> Option<(String, DecodingKey, Algorithm)> {
+        // Subscribe and capture the current generation before the leader election,
+        // so a leader that finishes between here and our `wait_for` below can't
+        // cause a missed wakeup: `wait_for` checks the channel's latest value, it
+        // doesn't need to have been "listening" at the moment of the bump.
+        let mut generation_rx = self.refresh_generation.subscribe();
+        let starting_generation = *generation_rx.borrow();
+
+        let is_leader = {
+            let mut refreshing = self.refreshing.lock().await;
+            if *refreshing {
+                false
+            } else {
+                *refreshing = true;
+                true
             }
-            
-            if let Ok(decoding_key) = DecodingKey::from_rsa_components(&jwk.n, &jwk.e) {
-                jwks.insert(jwk.kid.clone(), decoding_key);
-                debug!("Cached key: {}", jwk.kid);
-                successful_keys += 1;
+        };
+
+        if is_leader {
+            let within_cooldown = {
+                let last = self.last_miss_refresh.lock().await;
+                last.is_some_and(|t| t.elapsed() < Duration::from_secs(self.config.miss_refresh_cooldown_secs))
+            };
+
+            if within_cooldown {
+                debug!("Skipping JWKS refresh for unknown kid '{}': within cooldown window", kid);
             } else {
-                warn!("Failed to process RSA components for key: {}", jwk.kid);
+                warn!("Key '{}' not found in any team's cache, attempting to refresh JWKS for all teams", kid);
+                if let Err(()) = self.fetch_and_cache_keys().await {
+                    error!("Failed to refresh JWKS cache for one or more teams");
+                }
+                *self.last_miss_refresh.lock().await = Some(Instant::now());
             }
+
+            *self.refreshing.lock().await = false;
+            self.refresh_generation.send_modify(|g| *g = g.wrapping_add(1));
+        } else {
+            debug!("JWKS refresh already in progress for kid '{}', waiting for it to finish", kid);
+            let _ = tokio::time::timeout(
+                Duration::from_secs(30),
+                generation_rx.wait_for(|g| *g != starting_generation),
+            )
+            .await;
         }
-        
-        info!("Successfully cached {}/{} JWKS keys", successful_keys, keys.len());
-        Ok(())
+
+        find_key_for_kid(&*self.jwks.read().await, kid)
     }
 
-    // Start the periodic key refresh task
+    // Start the adaptive key refresh task: sleeps for the shortest per-team cadence
+    // (Cache-Control-derived after a successful fetch, the retry backoff after a
+    // failed one), then refreshes every team concurrently instead of polling on a
+    // fixed interval.
     fn start_key_refresh_task(self: Arc<Self>) {
         let state = Arc::clone(&self);
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(12 * 60 * 60)); // 12 hours
-            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-            
             loop {
-                interval.tick().await;
-                info!("Starting periodic JWKS key refresh");
-                
-                match state.fetch_and_cache_keys().await {
-                    Ok(()) => info!("Periodic JWKS key refresh completed successfully"),
-                    Err(()) => error!("Periodic JWKS key refresh failed"),
+                let delay = {
+                    let refresh_info = state.refresh_info.read().await;
+                    refresh_info.values().map(|info| info.next_refresh_secs).min().unwrap_or(state.config.default_refresh_secs)
+                };
+                debug!("Next JWKS key refresh in {}s", delay);
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+
+                info!("Starting periodic JWKS key refresh for all teams");
+                let results = state.fetch_and_cache_keys_per_team().await;
+                let failed_issuers: Vec<&str> = results
+                    .iter()
+                    .filter_map(|(issuer, r)| r.is_err().then_some(issuer.as_str()))
+                    .collect();
+
+                if failed_issuers.is_empty() {
+                    info!("Periodic JWKS key refresh completed successfully");
+                } else {
+                    error!(
+                        "Periodic JWKS key refresh failed for team(s) {:?}, retrying those in {}s",
+                        failed_issuers, state.config.retry_backoff_secs
+                    );
+                    // Only the teams that actually failed get their cadence reset; a
+                    // healthy team keeps the Cache-Control-derived next_refresh_secs
+                    // from this same round. The next sleep is still the minimum across
+                    // all teams, so while any team is failing every team is refetched
+                    // every retry_backoff_secs.
+                    state.apply_retry_cadence(&failed_issuers).await;
                 }
             }
         });
     }
 }
 
+// `jsonwebtoken::jwk::KeyAlgorithm` (the JWK `alg` field) and `jsonwebtoken::Algorithm`
+// (what `Validation`/`decode` take) are distinct enums with no conversion between
+// them; map the signature algorithms both enums share. Only RSA keys consult this
+// mapping, and symmetric (oct) keys are never cached.
+fn key_algorithm_to_algorithm(key_alg: KeyAlgorithm) -> Option<Algorithm> {
+    match key_alg {
+        KeyAlgorithm::HS256 => Some(Algorithm::HS256),
+        KeyAlgorithm::HS384 => Some(Algorithm::HS384),
+        KeyAlgorithm::HS512 => Some(Algorithm::HS512),
+        KeyAlgorithm::RS256 => Some(Algorithm::RS256),
+        KeyAlgorithm::RS384 => Some(Algorithm::RS384),
+        KeyAlgorithm::RS512 => Some(Algorithm::RS512),
+        KeyAlgorithm::ES256 => Some(Algorithm::ES256),
+        KeyAlgorithm::ES384 => Some(Algorithm::ES384),
+        KeyAlgorithm::PS256 => Some(Algorithm::PS256),
+        KeyAlgorithm::PS384 => Some(Algorithm::PS384),
+        KeyAlgorithm::PS512 => Some(Algorithm::PS512),
+        KeyAlgorithm::EdDSA => Some(Algorithm::EdDSA),
+        _ => None,
+    }
+}
+
+// Find the (issuer, key, algorithm) for a `kid` across every team's cache.
+fn find_key_for_kid(
+    jwks: &HashMap<String, HashMap<String, (DecodingKey, Algorithm)>>,
+    kid: &str,
+) -> Option<(String, DecodingKey, Algorithm)> {
+    jwks.iter().find_map(|(issuer, keys)| {
+        keys.get(kid).map(|(key, alg)| (issuer.clone(), key.clone(), *alg))
+    })
+}
+
 #[tokio::main]
 async fn main() {
     // Initialize tracing with environment variable support
@@ -193,18 +562,30 @@ async fn main() {
         .init();
 
     let config = Arc::new(AppConfig::from_env().expect("Failed to load configuration from environment"));
-    info!("Using Cloudflare team: {}", config.cf_issuer);
+    info!("Using Cloudflare teams: {}", config.teams.iter().map(|t| t.issuer.as_str()).collect::<Vec<_>>().join(", "));
     
     let state = Arc::new(AppState::new(config));
     
     // Fetch and cache keys at startup
     info!("Initializing JWKS key cache at startup");
-    if let Err(()) = state.fetch_and_cache_keys().await {
-        error!("Failed to fetch JWKS keys at startup - continuing anyway");
+    let startup_results = state.fetch_and_cache_keys_per_team().await;
+    let startup_failed_issuers: Vec<&str> = startup_results
+        .iter()
+        .filter_map(|(issuer, r)| r.is_err().then_some(issuer.as_str()))
+        .collect();
+    if !startup_failed_issuers.is_empty() {
+        error!(
+            "Failed to fetch JWKS keys at startup for team(s) {:?} - continuing anyway, retrying those in {}s",
+            startup_failed_issuers, state.config.retry_backoff_secs
+        );
+        // Without this, a team that's down at boot would sit on the 12h
+        // `default_refresh_secs` seeded in `AppState::new` until the first
+        // cache-miss-driven refresh happened to cover for it.
+        state.apply_retry_cadence(&startup_failed_issuers).await;
     }
     
-    // Start periodic key refresh task
-    info!("Starting periodic JWKS key refresh task (every 12 hours)");
+    // Start adaptive key refresh task, paced by the JWKS response's Cache-Control header
+    info!("Starting adaptive JWKS key refresh task");
     Arc::clone(&state).start_key_refresh_task();
     
     let app = Router::new()
@@ -257,9 +638,18 @@ async fn auth_handler(
     
     // Validate JWT
     match validate_jwt(&jwt, &state, &aud).await {
-        Ok(_) => {
+        Ok(token_data) => {
             debug!("JWT validation successful");
-            create_response(StatusCode::NO_CONTENT) // 204 for Nginx auth_request
+            let claims = &token_data.claims;
+
+            if !authorize(claims, &state.config, &aud) {
+                warn!("Authenticated identity '{}' not authorized for audience '{}'", claims.email.as_deref().unwrap_or("<no email>"), aud);
+                return create_response(StatusCode::FORBIDDEN);
+            }
+
+            let (status, mut headers) = create_response(StatusCode::NO_CONTENT); // 204 for Nginx auth_request
+            forward_claim_headers(&mut headers, claims, &state.config.forwarded_claims);
+            (status, headers)
         },
         Err(_) => {
             warn!("JWT validation failed");
@@ -268,6 +658,74 @@ async fn auth_handler(
     }
 }
 
+// Authorization check: a valid signature only proves the token came from the
+// configured identity provider, not that this specific identity is permitted
+// for `aud`. Returns true when no policy is configured (signature-only auth).
+fn authorize(claims: &Claims, config: &AppConfig, aud: &str) -> bool {
+    let policy = policy_for_audience(config, aud);
+    policy.allows(claims.email.as_deref(), &extract_groups(claims))
+}
+
+// Cloudflare Access surfaces group membership as a custom "groups" claim (an
+// array of strings) on tokens issued behind a group-restricted policy.
+fn extract_groups(claims: &Claims) -> Vec<String> {
+    claims
+        .extra
+        .get("groups")
+        .and_then(|v| v.as_array())
+        .map(|groups| groups.iter().filter_map(|g| g.as_str().map(String::from)).collect())
+        .unwrap_or_default()
+}
+
+// Copy the configured claim -> header mappings onto the auth_request response so
+// nginx can re-inject identity via auth_request_set. Values are rejected unless
+// they're visible ASCII, since header values land straight into the response
+// line and a CR/LF in a claim could otherwise inject headers upstream.
+fn forward_claim_headers(headers: &mut HeaderMap, claims: &Claims, mappings: &[(String, String)]) {
+    for (claim_name, header_name) in mappings {
+        let Some(value) = claim_value(claims, claim_name) else {
+            continue;
+        };
+        if !is_visible_ascii(&value) {
+            warn!("Refusing to forward claim '{}': contains non-visible-ASCII characters", claim_name);
+            continue;
+        }
+        let name = match HeaderName::from_bytes(header_name.as_bytes()) {
+            Ok(name) => name,
+            Err(e) => {
+                warn!("Skipping invalid claim header name '{}': {:?}", header_name, e);
+                continue;
+            }
+        };
+        let val = match HeaderValue::from_str(&value) {
+            Ok(val) => val,
+            Err(e) => {
+                warn!("Skipping unforwardable value for claim '{}': {:?}", claim_name, e);
+                continue;
+            }
+        };
+        headers.insert(name, val);
+    }
+}
+
+// Look up a claim by name across the well-known fields and the flattened extras.
+fn claim_value(claims: &Claims, name: &str) -> Option<String> {
+    match name {
+        "email" => claims.email.clone(),
+        "sub" => claims.sub.clone(),
+        "iss" => Some(claims.iss.clone()),
+        "aud" => Some(claims.aud.clone()),
+        _ => claims.extra.get(name).map(|value| match value {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        }),
+    }
+}
+
+fn is_visible_ascii(s: &str) -> bool {
+    s.bytes().all(|b| (0x20..=0x7e).contains(&b))
+}
+
 // Health check endpoint for load balancers and monitoring
 async fn health_handler() -> impl IntoResponse {
     create_response(StatusCode::OK)
@@ -275,15 +733,35 @@ async fn health_handler() -> impl IntoResponse {
 
 // Manual key refresh endpoint for operational purposes
 async fn refresh_keys_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    info!("Manual JWKS key refresh requested");
-    
+    info!("Manual JWKS key refresh requested for all teams");
+
     match state.fetch_and_cache_keys().await {
         Ok(()) => {
             info!("Manual JWKS key refresh completed successfully");
-            create_response(StatusCode::OK)
+            let (status, mut headers) = create_response(StatusCode::OK);
+            let (next_refresh_secs, max_age_report) = {
+                let refresh_info = state.refresh_info.read().await;
+                let next_refresh_secs = refresh_info.values().map(|info| info.next_refresh_secs).min().unwrap_or(state.config.default_refresh_secs);
+                // issuer=max-age pairs (max-age is "none" when the last fetch had no
+                // Cache-Control max-age), so operators can see each team's actual
+                // last-known cadence rather than just the aggregated next refresh.
+                let max_age_report = refresh_info
+                    .iter()
+                    .map(|(issuer, info)| format!("{}={}", issuer, info.max_age.map(|a| a.to_string()).unwrap_or_else(|| "none".to_string())))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                (next_refresh_secs, max_age_report)
+            };
+            if let Ok(value) = HeaderValue::from_str(&next_refresh_secs.to_string()) {
+                headers.insert("x-next-refresh-seconds", value);
+            }
+            if let Ok(value) = HeaderValue::from_str(&max_age_report) {
+                headers.insert("x-jwks-max-age-seconds", value);
+            }
+            (status, headers)
         },
         Err(()) => {
-            error!("Manual JWKS key refresh failed");
+            error!("Manual JWKS key refresh failed for one or more teams");
             create_response(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
@@ -341,54 +819,44 @@ async fn validate_jwt(token: &str, state: &AppState, aud: &str) -> Result<TokenD
     })?;
     debug!("JWT kid: {}", kid);
     
-    // Get decoding key from cache
-    let key = {
-        let jwks = state.jwks.read().await;
-        jwks.get(&kid).cloned()
-    };
-    
-    let decoding_key = if let Some(key) = key {
+    // Get decoding key from cache, across all teams
+    let key = find_key_for_kid(&*state.jwks.read().await, &kid);
+
+    let (issuer, decoding_key, algorithm) = if let Some(key) = key {
         debug!("Using cached key for kid: {}", kid);
         key
     } else {
-        warn!("Key '{}' not found in cache, attempting to refresh JWKS", kid);
-        // Try to refresh the cache in case new keys were added
-        if let Err(()) = state.fetch_and_cache_keys().await {
-            error!("Failed to refresh JWKS cache");
-            return Err(());
-        }
-        
-        // Try to get the key again after refresh
-        let jwks = state.jwks.read().await;
-        jwks.get(&kid).cloned().ok_or_else(|| {
+        state.refresh_on_cache_miss(&kid).await.ok_or_else(|| {
             error!("Key '{}' still not found after JWKS refresh", kid);
             ()
         })?
     };
-    
-    // Validate
-    let mut validation = Validation::new(Algorithm::RS256);
+
+    // Validate. Any configured team's issuer is accepted here; the specific
+    // issuer the key was cached under is then checked explicitly below.
+    let mut validation = Validation::new(algorithm);
     validation.set_audience(&[aud]);
-    validation.iss = Some(HashSet::from([state.config.cf_issuer.clone()]));
-    
-    debug!("Validating JWT with audience: {} and issuer: {}", aud, state.config.cf_issuer);
+    validation.iss = Some(state.config.teams.iter().map(|t| t.issuer.clone()).collect::<HashSet<_>>());
+
+    debug!("Validating JWT with audience: {} against issuer: {}", aud, issuer);
     let token_data = decode::<Claims>(token, &decoding_key, &validation).map_err(|e| {
         error!("JWT validation error: {:?}", e);
         ()
     })?;
-    
+
     // Additional validation of claims
     let claims = &token_data.claims;
-    
+
     // Validate expiration (jsonwebtoken already checks this, but we can log it)
     debug!("JWT expires at: {}", claims.exp);
-    
-    // Validate issuer (also checked by jsonwebtoken, but we can verify it matches our expectation)
-    if claims.iss != state.config.cf_issuer {
-        error!("JWT issuer mismatch: expected {}, got {}", state.config.cf_issuer, claims.iss);
+
+    // The key that verified this signature was cached under `issuer`; make sure the
+    // token's own `iss` claim actually matches it rather than some other configured team.
+    if claims.iss != issuer {
+        error!("JWT issuer mismatch: key was cached under {}, but token claims iss {}", issuer, claims.iss);
         return Err(());
     }
-    
+
     // Validate audience (also checked by jsonwebtoken)
     if claims.aud != aud {
         error!("JWT audience mismatch: expected {}, got {}", aud, claims.aud);
@@ -403,25 +871,34 @@ async fn validate_jwt(token: &str, state: &AppState, aud: &str) -> Result<TokenD
     Ok(token_data)
 }
 
-// fetch_jwks with detailed error logging
-async fn fetch_jwks(client: &Client, url: &str) -> Result<Vec<Jwk>, ()> {
+// fetch_jwks with detailed error logging. Also returns the `max-age` directive
+// from the response's Cache-Control header, if present, so callers can pace
+// the next refresh instead of relying on a fixed interval.
+async fn fetch_jwks(client: &Client, url: &str) -> Result<(JwkSet, Option<u64>), ()> {
     debug!("Making HTTP request to: {}", url);
     let resp = client.get(url).send().await.map_err(|e| {
         error!("HTTP request failed: {:?}", e);
         ()
     })?;
-    
+
     debug!("HTTP response status: {}", resp.status());
     if !resp.status().is_success() {
         error!("HTTP request returned non-success status: {}", resp.status());
         return Err(());
     }
-    
-    let jwks: Jwks = resp.json().await.map_err(|e| {
+
+    let max_age = resp
+        .headers()
+        .get(reqwest::header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_max_age);
+    debug!("JWKS response Cache-Control max-age: {:?}", max_age);
+
+    let jwk_set: JwkSet = resp.json().await.map_err(|e| {
         error!("Failed to parse JSON response: {:?}", e);
         ()
     })?;
-    
-    debug!("Successfully parsed JWKS with {} keys", jwks.keys.len());
-    Ok(jwks.keys)
+
+    debug!("Successfully parsed JWKS with {} keys", jwk_set.keys.len());
+    Ok((jwk_set, max_age))
 }